@@ -0,0 +1,44 @@
+use alloc::string::String;
+use core::fmt;
+use x86_64::registers::rflags::RFlags;
+
+#[derive(Debug)]
+pub enum Error {
+    AllocError(&'static str),
+    InvalidValue(String),
+    VmFailValid(String),
+    VmFailInvalid(String),
+    NotSupported(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::AllocError(msg) => write!(f, "Allocation failed: {}", msg),
+            Error::InvalidValue(msg) => write!(f, "Invalid value: {}", msg),
+            Error::VmFailValid(msg) => write!(f, "VM instruction failed (valid): {}", msg),
+            Error::VmFailInvalid(msg) => write!(f, "VM instruction failed (invalid): {}", msg),
+            Error::NotSupported(msg) => write!(f, "Not supported: {}", msg),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+/// Check the RFLAGS output of a VMX instruction and turn the CF/ZF
+/// status into the appropriate `Error` variant.
+///
+/// See Intel SDM 30.2 "Conventions" for how VMX instructions report
+/// failure: ZF=1 indicates a VMfailValid (an error code is available
+/// in the VM-instruction error field), CF=1 indicates a VMfailInvalid
+/// (no current VMCS, so no error field is available).
+pub fn check_vm_insruction(rflags: u64, message: String) -> Result<()> {
+    let flags = RFlags::from_bits_truncate(rflags);
+    if flags.contains(RFlags::ZERO_FLAG) {
+        Err(Error::VmFailValid(message))
+    } else if flags.contains(RFlags::CARRY_FLAG) {
+        Err(Error::VmFailInvalid(message))
+    } else {
+        Ok(())
+    }
+}
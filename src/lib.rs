@@ -0,0 +1,18 @@
+#![no_std]
+#![feature(asm)]
+#![feature(naked_functions)]
+#![feature(never_type)]
+
+#[macro_use]
+extern crate alloc;
+#[macro_use]
+extern crate bitflags;
+#[macro_use]
+extern crate log;
+
+pub mod error;
+pub mod memory;
+pub mod registers;
+pub mod vm;
+pub mod vmcs;
+pub mod vmx;
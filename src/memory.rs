@@ -0,0 +1,126 @@
+//! Guest-physical memory and the Extended Page Tables (EPT) that back it.
+
+use crate::error::{Error, Result};
+use x86_64::structures::paging::frame::PhysFrame;
+use x86_64::structures::paging::page::Size4KiB;
+use x86_64::structures::paging::FrameAllocator;
+use x86_64::PhysAddr;
+
+const ENTRY_COUNT: usize = 512;
+
+bitflags! {
+    /// EPT paging-structure entry flags (Intel SDM 28.2.2).
+    pub struct EptEntryFlags: u64 {
+        const READ = 1 << 0;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+        /// EPT memory type, for PTEs only (bits 3-5). We only ever use
+        /// write-back (6), so this is the value already shifted into place.
+        const MEMORY_TYPE_WB = 6 << 3;
+    }
+}
+
+/// A guest-physical address. Distinct from a host `PhysAddr` so that the
+/// two address spaces can't be mixed up at a type level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GuestPhysAddr(u64);
+
+impl GuestPhysAddr {
+    pub fn new(addr: u64) -> Self {
+        GuestPhysAddr(addr)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// The index into each of the four EPT paging-structure levels that
+    /// this address would be found at.
+    fn table_indices(self) -> [usize; 4] {
+        [
+            ((self.0 >> 39) & 0x1ff) as usize,
+            ((self.0 >> 30) & 0x1ff) as usize,
+            ((self.0 >> 21) & 0x1ff) as usize,
+            ((self.0 >> 12) & 0x1ff) as usize,
+        ]
+    }
+}
+
+#[repr(C, align(4096))]
+struct EptTable {
+    entries: [u64; ENTRY_COUNT],
+}
+
+impl EptTable {
+    fn clear(&mut self) {
+        for entry in self.entries.iter_mut() {
+            *entry = 0;
+        }
+    }
+}
+
+fn table_entry_frame(entry: u64) -> PhysFrame<Size4KiB> {
+    PhysFrame::containing_address(PhysAddr::new(entry & 0x000f_ffff_ffff_f000))
+}
+
+fn table_ptr(frame: PhysFrame<Size4KiB>) -> *mut EptTable {
+    frame.start_address().as_u64() as *mut EptTable
+}
+
+/// The top-level (PML4) table of an EPT paging structure.
+pub struct EptPml4Table {
+    frame: PhysFrame<Size4KiB>,
+}
+
+impl EptPml4Table {
+    /// Take ownership of `frame` and initialize it as an empty PML4.
+    pub fn new(frame: &mut PhysFrame<Size4KiB>) -> Result<Self> {
+        let table = unsafe { &mut *table_ptr(*frame) };
+        table.clear();
+        Ok(EptPml4Table { frame: *frame })
+    }
+
+    pub fn frame(&self) -> PhysFrame<Size4KiB> {
+        self.frame
+    }
+}
+
+/// Map a single guest-physical page to a host physical frame, allocating
+/// any intermediate EPT paging structures that don't already exist.
+pub fn map_guest_memory(
+    alloc: &mut impl FrameAllocator<Size4KiB>,
+    pml4: &mut EptPml4Table,
+    addr: GuestPhysAddr,
+    host_frame: PhysFrame<Size4KiB>,
+    writable: bool,
+) -> Result<()> {
+    let indices = addr.table_indices();
+
+    let mut table = unsafe { &mut *table_ptr(pml4.frame) };
+    // Walk the PML4 -> PDPT -> PD levels, allocating a new (zeroed) table
+    // for any entry that isn't present yet.
+    for &index in &indices[..3] {
+        let entry = table.entries[index];
+        let next_frame = if entry & EptEntryFlags::READ.bits() != 0 {
+            table_entry_frame(entry)
+        } else {
+            let frame = alloc
+                .allocate_frame()
+                .ok_or(Error::AllocError("Failed to allocate EPT table frame"))?;
+            unsafe { (&mut *table_ptr(frame)).clear() };
+            table.entries[index] = frame.start_address().as_u64()
+                | (EptEntryFlags::READ | EptEntryFlags::WRITE | EptEntryFlags::EXECUTE).bits();
+            frame
+        };
+        table = unsafe { &mut *table_ptr(next_frame) };
+    }
+
+    let mut flags = EptEntryFlags::READ | EptEntryFlags::EXECUTE | EptEntryFlags::MEMORY_TYPE_WB;
+    if writable {
+        flags |= EptEntryFlags::WRITE;
+    }
+
+    table.entries[indices[3]] = host_frame.start_address().as_u64() | flags.bits();
+
+    Ok(())
+}
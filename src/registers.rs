@@ -0,0 +1,81 @@
+//! Raw register and MSR helpers that are not covered by the `x86_64`
+//! crate's higher-level wrappers.
+
+use x86_64::VirtAddr;
+
+/// IA32_VMX_BASIC
+pub const MSR_IA32_VMX_BASIC: u32 = 0x480;
+/// IA32_VMX_PINBASED_CTLS
+pub const MSR_IA32_VMX_PINBASED_CTLS: u32 = 0x481;
+/// IA32_VMX_PROCBASED_CTLS
+pub const MSR_IA32_VMX_PROCBASED_CTLS: u32 = 0x482;
+/// IA32_VMX_EXIT_CTLS
+pub const MSR_IA32_VMX_EXIT_CTLS: u32 = 0x483;
+/// IA32_VMX_ENTRY_CTLS
+pub const MSR_IA32_VMX_ENTRY_CTLS: u32 = 0x484;
+/// IA32_VMX_CR0_FIXED0
+pub const MSR_IA32_VMX_CR0_FIXED0: u32 = 0x486;
+/// IA32_VMX_CR0_FIXED1
+pub const MSR_IA32_VMX_CR0_FIXED1: u32 = 0x487;
+/// IA32_VMX_CR4_FIXED0
+pub const MSR_IA32_VMX_CR4_FIXED0: u32 = 0x488;
+/// IA32_VMX_CR4_FIXED1
+pub const MSR_IA32_VMX_CR4_FIXED1: u32 = 0x489;
+/// IA32_VMX_PROCBASED_CTLS2
+pub const MSR_IA32_VMX_PROCBASED_CTLS2: u32 = 0x48B;
+/// IA32_EFER
+pub const MSR_IA32_EFER: u32 = 0xc000_0080;
+
+/// CR4, read as a raw bitmask rather than the `x86_64` crate's typed
+/// `Cr4Flags`, since the VMCS host/guest-state fields just want the bits.
+pub struct Cr4;
+
+impl Cr4 {
+    pub fn read() -> u64 {
+        let value: u64;
+        unsafe {
+            asm!("mov %cr4, $0"
+                 : "=r"(value)
+                 ::: "volatile");
+        }
+        value
+    }
+}
+
+/// The base address of the GDTR, as loaded by `sgdt`.
+pub struct GdtrBase;
+
+impl GdtrBase {
+    pub fn read() -> VirtAddr {
+        let mut gdtr: [u8; 10] = [0; 10];
+        unsafe {
+            asm!("sgdt ($0)"
+                 :: "r"(&mut gdtr as *mut _ as u64)
+                 : "memory"
+                 : "volatile");
+        }
+        let base = u64::from_le_bytes([
+            gdtr[2], gdtr[3], gdtr[4], gdtr[5], gdtr[6], gdtr[7], gdtr[8], gdtr[9],
+        ]);
+        VirtAddr::new(base)
+    }
+}
+
+/// The base address of the IDTR, as loaded by `sidt`.
+pub struct IdtrBase;
+
+impl IdtrBase {
+    pub fn read() -> VirtAddr {
+        let mut idtr: [u8; 10] = [0; 10];
+        unsafe {
+            asm!("sidt ($0)"
+                 :: "r"(&mut idtr as *mut _ as u64)
+                 : "memory"
+                 : "volatile");
+        }
+        let base = u64::from_le_bytes([
+            idtr[2], idtr[3], idtr[4], idtr[5], idtr[6], idtr[7], idtr[8], idtr[9],
+        ]);
+        VirtAddr::new(base)
+    }
+}
@@ -6,8 +6,6 @@ use crate::vmx;
 use alloc::vec::Vec;
 use x86_64::registers::control::{Cr0, Cr3};
 use x86_64::registers::model_specific::{Efer, FsBase, GsBase, Msr};
-use x86_64::registers::rflags;
-use x86_64::registers::rflags::RFlags;
 use x86_64::structures::paging::frame::PhysFrame;
 use x86_64::structures::paging::page::Size4KiB;
 use x86_64::structures::paging::FrameAllocator;
@@ -16,6 +14,7 @@ use x86_64::PhysAddr;
 pub struct VirtualMachineConfig {
     images: Vec<(Vec<u8>, GuestPhysAddr)>,
     memory: u64, // number of 4k pages
+    start_addr: GuestPhysAddr,
 }
 
 impl VirtualMachineConfig {
@@ -23,6 +22,7 @@ impl VirtualMachineConfig {
         VirtualMachineConfig {
             images: vec![],
             memory: memory,
+            start_addr: start_addr,
         }
     }
 
@@ -36,6 +36,9 @@ pub struct VirtualMachine {
     vmcs: vmcs::Vmcs,
     config: VirtualMachineConfig,
     stack: PhysFrame<Size4KiB>,
+    msr_bitmap: vmx::MsrBitmap,
+    io_bitmap: vmx::IoBitmap,
+    io_devices: Vec<(u16, alloc::boxed::Box<dyn IoDevice>)>,
 }
 
 impl VirtualMachine {
@@ -50,48 +53,70 @@ impl VirtualMachine {
             .allocate_frame()
             .ok_or(Error::AllocError("Failed to allocate VM stack"))?;
 
-        vmcs.with_active_vmcs(vmx, |mut vmcs| {
-            Self::setup_ept(&mut vmcs, alloc)?;
+        let (msr_bitmap, io_bitmap) = vmcs.with_active_vmcs(vmx, |mut vmcs| {
+            Self::setup_ept(&mut vmcs, alloc, &config)?;
             Self::initialize_host_vmcs(alloc, &mut vmcs, &stack)?;
-            Self::initialize_guest_vmcs(&mut vmcs)?;
-            Self::initialize_ctrl_vmcs(&mut vmcs, alloc)?;
-            Ok(())
+            Self::initialize_guest_vmcs(&mut vmcs, &config)?;
+            Self::initialize_ctrl_vmcs(&mut vmcs, alloc)
         })?;
 
         Ok(Self {
             vmcs: vmcs,
             config: config,
             stack: stack,
+            msr_bitmap,
+            io_bitmap,
+            io_devices: vec![],
         })
     }
 
+    /// Route `IN`/`OUT` on `port` to `device` instead of the default of
+    /// logging and ignoring the access. Marks the port as trapped in the
+    /// IO bitmap so the guest's accesses actually reach the IO-exit
+    /// handler with something to dispatch to.
+    pub fn register_io_device(&mut self, port: u16, device: alloc::boxed::Box<dyn IoDevice>) -> Result<()> {
+        self.io_bitmap.trap_port(port)?;
+        self.io_devices.push((port, device));
+        Ok(())
+    }
+
+    /// Build the EPT mapping for `config`: `config.memory` pages of guest
+    /// physical memory, mapped contiguously from guest-physical address 0
+    /// onto freshly allocated host frames, with each loaded image copied
+    /// into the backing frames at its configured guest-physical address.
     fn setup_ept(
         vmcs: &mut vmcs::TemporaryActiveVmcs,
         alloc: &mut impl FrameAllocator<Size4KiB>,
+        config: &VirtualMachineConfig,
     ) -> Result<PhysFrame<Size4KiB>> {
-        //FIXME: very hacky ept setup. Just testing for now
-        use crate::memory::{self, EptPml4Table};
-        use x86_64::structures::paging::FrameAllocator;
+        use crate::memory::{self, EptPml4Table, GuestPhysAddr};
+
         let mut ept_pml4_frame = alloc
             .allocate_frame()
-            .expect("Failed to allocate pml4 frame");
-        let mut ept_pml4 =
-            EptPml4Table::new(&mut ept_pml4_frame).expect("Failed to create pml4 table");
+            .ok_or(Error::AllocError("Failed to allocate pml4 frame"))?;
+        let mut ept_pml4 = EptPml4Table::new(&mut ept_pml4_frame)?;
+
+        let mut guest_frames = Vec::with_capacity(config.memory as usize);
+        for i in 0..config.memory {
+            let host_frame = alloc
+                .allocate_frame()
+                .ok_or(Error::AllocError("Failed to allocate guest memory frame"))?;
+            memory::map_guest_memory(
+                alloc,
+                &mut ept_pml4,
+                GuestPhysAddr::new(i * 0x1000),
+                host_frame,
+                true,
+            )?;
+            guest_frames.push(host_frame);
+        }
 
-        let mut host_frame = alloc
-            .allocate_frame()
-            .expect("Failed to allocate host frame");
-
-        memory::map_guest_memory(
-            alloc,
-            &mut ept_pml4,
-            memory::GuestPhysAddr::new(0xFFFFF000),
-            host_frame,
-            false,
-        )?;
+        for (image, addr) in config.images.iter() {
+            Self::copy_image_to_guest_memory(&guest_frames, *addr, image)?;
+        }
 
-        let mut eptp = ept_pml4_frame.start_address().as_u64() ;
-        eptp |= 6;// query the bit 8 of the VPID_EPT VMX CAP
+        let mut eptp = ept_pml4_frame.start_address().as_u64();
+        eptp |= 6; // query the bit 8 of the VPID_EPT VMX CAP
         eptp |= (4 - 1) << 3; // page-walk length:4
         eptp |= 1 << 6; // enable acccessed and dirty marking
 
@@ -101,20 +126,74 @@ impl VirtualMachine {
         Ok(ept_pml4_frame)
     }
 
+    /// Copy `image` into the guest memory frames allocated by
+    /// `setup_ept`, starting at guest-physical address `addr`, splitting
+    /// the copy at frame boundaries as needed.
+    fn copy_image_to_guest_memory(
+        guest_frames: &[PhysFrame<Size4KiB>],
+        addr: GuestPhysAddr,
+        image: &[u8],
+    ) -> Result<()> {
+        let mut remaining = &image[..];
+        let mut offset = addr.as_u64();
+
+        while !remaining.is_empty() {
+            let frame_index = (offset / 0x1000) as usize;
+            let frame = guest_frames.get(frame_index).ok_or_else(|| {
+                Error::InvalidValue("Guest image does not fit in configured memory".into())
+            })?;
+
+            let frame_offset = (offset % 0x1000) as usize;
+            let copy_len = core::cmp::min(remaining.len(), 0x1000 - frame_offset);
+
+            let dst = (frame.start_address().as_u64() as usize + frame_offset) as *mut u8;
+            unsafe {
+                core::ptr::copy_nonoverlapping(remaining.as_ptr(), dst, copy_len);
+            }
+
+            remaining = &remaining[copy_len..];
+            offset += copy_len as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Clamp `value` against a pair of IA32_VMX_CRx_FIXED{0,1} MSRs: bits
+    /// fixed to 1 in FIXED0 are forced on, bits fixed to 0 in FIXED1 are
+    /// forced off, and bits that are 0 in FIXED0/1 in FIXED1 are left as
+    /// `value` had them. Entering VMX non-root (or root) operation with a
+    /// CR0/CR4 that disagrees with these fails with an invalid-guest-state
+    /// or VM-entry error on hardware that enforces them.
+    fn sanitize_cr(value: u64, fixed0_msr: u32, fixed1_msr: u32) -> u64 {
+        let fixed0 = unsafe { Msr::new(fixed0_msr).read() };
+        let fixed1 = unsafe { Msr::new(fixed1_msr).read() };
+        (value & fixed1) | fixed0
+    }
+
     fn initialize_host_vmcs(
         alloc: &mut impl FrameAllocator<Size4KiB>,
         vmcs: &mut vmcs::TemporaryActiveVmcs,
         stack: &PhysFrame<Size4KiB>,
     ) -> Result<()> {
-        //TODO: Check with MSR_IA32_VMX_CR0_FIXED0/1 that these bits are valid
-        vmcs.write_field(vmcs::VmcsField::HostCr0, Cr0::read().bits())?;
+        let host_cr0 = Self::sanitize_cr(
+            Cr0::read().bits(),
+            registers::MSR_IA32_VMX_CR0_FIXED0,
+            registers::MSR_IA32_VMX_CR0_FIXED1,
+        );
+        vmcs.write_field(vmcs::VmcsField::HostCr0, host_cr0)?;
 
         let current_cr3 = Cr3::read();
         vmcs.write_field(
             vmcs::VmcsField::HostCr3,
             current_cr3.0.start_address().as_u64() | current_cr3.1.bits(),
         )?;
-        vmcs.write_field(vmcs::VmcsField::HostCr4, Cr4::read())?;
+
+        let host_cr4 = Self::sanitize_cr(
+            Cr4::read(),
+            registers::MSR_IA32_VMX_CR4_FIXED0,
+            registers::MSR_IA32_VMX_CR4_FIXED1,
+        );
+        vmcs.write_field(vmcs::VmcsField::HostCr4, host_cr4)?;
 
         vmcs.write_field(vmcs::VmcsField::HostEsSelector, 0x00)?;
         vmcs.write_field(vmcs::VmcsField::HostCsSelector, 0xe008)?;
@@ -142,7 +221,13 @@ impl VirtualMachine {
             tr_base_frame.start_address().as_u64(),
         );
 
-        vmcs.write_field(vmcs::VmcsField::HostRsp, stack.start_address().as_u64())?;
+        // Stacks grow down, and the first instruction the VM-exit handler
+        // executes is a `push`, so HostRsp must point one past the end of
+        // the allocated frame rather than at its base.
+        vmcs.write_field(
+            vmcs::VmcsField::HostRsp,
+            stack.start_address().as_u64() + 0x1000,
+        )?;
         vmcs.write_field(vmcs::VmcsField::HostIa32Efer, Efer::read().bits())?;
 
         vmcs.write_field(vmcs::VmcsField::HostRip, vmx::vmexit_handler_wrapper as u64)?;
@@ -150,7 +235,10 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn initialize_guest_vmcs(vmcs: &mut vmcs::TemporaryActiveVmcs) -> Result<()> {
+    fn initialize_guest_vmcs(
+        vmcs: &mut vmcs::TemporaryActiveVmcs,
+        config: &VirtualMachineConfig,
+    ) -> Result<()> {
         vmcs.write_field(vmcs::VmcsField::GuestEsSelector, 0x00)?;
         vmcs.write_field(vmcs::VmcsField::GuestCsSelector, 0x00)?;
         vmcs.write_field(vmcs::VmcsField::GuestSsSelector, 0x00)?;
@@ -201,31 +289,59 @@ impl VirtualMachine {
         //TODO: get actual EFER (use MSR for vt-x v1)
         vmcs.write_field(vmcs::VmcsField::GuestIa32Efer, 0x00)?;
 
-        let (guest_cr0, guest_cr4) = unsafe {
-            let mut cr0_fixed0 = Msr::new(registers::MSR_IA32_VMX_CR0_FIXED0).read();
-            cr0_fixed0 &= !(1 << 0); // disable PE
-            cr0_fixed0 &= !(1 << 31); // disable PG
-            let cr4_fixed0 = Msr::new(registers::MSR_IA32_VMX_CR4_FIXED0).read();
-            (cr0_fixed0, cr4_fixed0)
-        };
-        vmcs.write_field(vmcs::VmcsField::GuestCr0, guest_cr0);
-        vmcs.write_field(vmcs::VmcsField::GuestCr4, guest_cr4);
+        // Real-mode entry: sanitize against FIXED0/FIXED1 like everywhere
+        // else, then force PE and PG back off since the guest is meant to
+        // start in real mode regardless of what FIXED0 would otherwise
+        // require (unrestricted-guest support, enabled elsewhere, is what
+        // makes this legal).
+        let guest_cr0 = Self::sanitize_cr(
+            0,
+            registers::MSR_IA32_VMX_CR0_FIXED0,
+            registers::MSR_IA32_VMX_CR0_FIXED1,
+        ) & !(1 << 0)
+            & !(1 << 31);
+        let guest_cr4 = Self::sanitize_cr(
+            0,
+            registers::MSR_IA32_VMX_CR4_FIXED0,
+            registers::MSR_IA32_VMX_CR4_FIXED1,
+        );
+        vmcs.write_field(vmcs::VmcsField::GuestCr0, guest_cr0)?;
+        vmcs.write_field(vmcs::VmcsField::GuestCr4, guest_cr4)?;
 
         vmcs.write_field(vmcs::VmcsField::GuestCr3, 0x00)?;
 
-        //TODO: set to a value from the config
-        vmcs.write_field(vmcs::VmcsField::GuestRip, 0xFFFFF000)?;
+        vmcs.write_field(vmcs::VmcsField::GuestRip, config.start_addr.as_u64())?;
+
+        Ok(())
+    }
 
+    /// Confirm `MSR_IA32_VMX_PROCBASED_CTLS2` (SDM A.3.1) reports every
+    /// bit in `flags` as settable before asking the VMCS to turn them on.
+    /// `write_with_fixed` alone would silently mask an unsupported bit
+    /// back off instead of telling the caller it didn't get what it
+    /// asked for, which for `UNRESTRICTED_GUEST` means an invalid guest
+    /// state at entry instead of a clean error here.
+    fn require_secondary_exec_support(flags: vmcs::SecondaryExecFlags) -> Result<()> {
+        let caps = unsafe { Msr::new(registers::MSR_IA32_VMX_PROCBASED_CTLS2).read() };
+        let allowed1 = caps >> 32;
+        if flags.bits() & !allowed1 != 0 {
+            return Err(Error::NotSupported(
+                "CPU does not support EPT, VPID, and unrestricted guest",
+            ));
+        }
         Ok(())
     }
 
     fn initialize_ctrl_vmcs(
         vmcs: &mut vmcs::TemporaryActiveVmcs,
         alloc: &mut impl FrameAllocator<Size4KiB>,
-    ) -> Result<()> {
+    ) -> Result<(vmx::MsrBitmap, vmx::IoBitmap)> {
         vmcs.write_with_fixed(
             vmcs::VmcsField::CpuBasedVmExecControl,
-            vmcs::CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS.bits(),
+            (vmcs::CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS
+                | vmcs::CpuBasedCtrlFlags::USE_MSR_BITMAPS
+                | vmcs::CpuBasedCtrlFlags::USE_IO_BITMAPS)
+                .bits(),
             registers::MSR_IA32_VMX_PROCBASED_CTLS,
         )?;
 
@@ -252,12 +368,19 @@ impl VirtualMachine {
             registers::MSR_IA32_VMX_ENTRY_CTLS,
         )?;
 
-        // vmcs.write_with_fixed(
-        //     vmcs::VmcsField::SecondaryVmExecControl,
-        //     (vmcs::SecondaryExecFlags::ENABLE_EPT)
-        //         .bits(),
-        //     registers::MSR_IA32_VMX_PROCBASED_CTLS2,
-        // )?;
+        // EPT is already programmed (`setup_ept` wrote `EptPointer`/
+        // `VirtualProcessorId`) but stays inert until this turns it on,
+        // and the real-mode guest entered with PE/PG cleared needs
+        // `UNRESTRICTED_GUEST` to be allowed to run at all.
+        let secondary_flags = vmcs::SecondaryExecFlags::ENABLE_EPT
+            | vmcs::SecondaryExecFlags::ENABLE_VPID
+            | vmcs::SecondaryExecFlags::UNRESTRICTED_GUEST;
+        Self::require_secondary_exec_support(secondary_flags)?;
+        vmcs.write_with_fixed(
+            vmcs::VmcsField::SecondaryVmExecControl,
+            secondary_flags.bits(),
+            registers::MSR_IA32_VMX_PROCBASED_CTLS2,
+        )?;
 
         let vapic = alloc
             .allocate_frame()
@@ -280,20 +403,39 @@ impl VirtualMachine {
         let flags = vmcs::SecondaryExecFlags::from_bits_truncate(field);
         info!("Secondary Flags: {:?}", flags);
 
-        //FIXME: this leaks the bitmap frames
-        let bitmap_a = alloc
-            .allocate_frame()
-            .ok_or(Error::AllocError("Failed to allocate IO bitmap"))?;
-        let bitmap_b = alloc
-            .allocate_frame()
-            .ok_or(Error::AllocError("Failed to allocate IO bitmap"))?;
+        // Trap the MSRs this crate virtualizes (IA32_EFER, since
+        // `GuestIa32Efer` is software state until a real value is
+        // written) and let every other MSR the bitmap covers through
+        // untouched. Returned
+        // rather than dropped here, so `VirtualMachine` keeps the handle
+        // for as long as the VMCS points at it -- this crate has no
+        // frame deallocator, so the underlying frame isn't freed either
+        // way, but without the handle nothing could touch the bitmap
+        // again after setup.
+        let mut msr_bitmap = vmx::MsrBitmap::new(alloc)?;
+        msr_bitmap.intercept_read(registers::MSR_IA32_EFER)?;
+        msr_bitmap.intercept_write(registers::MSR_IA32_EFER)?;
+        vmcs.write_field(
+            vmcs::VmcsField::MsrBitmap,
+            msr_bitmap.frame().start_address().as_u64(),
+        )?;
+
+        // Trap every port by default; `VirtualMachine::register_io_device`
+        // routes specific ones to a device, and accesses to the rest are
+        // logged and ignored by the IO-exit handler. Returned rather than
+        // dropped here, so `VirtualMachine` keeps the handle needed to
+        // mutate the bitmap later (see `IoBitmap`'s doc comment).
+        let mut io_bitmap = vmx::IoBitmap::new(alloc)?;
+        for port in 0..=0xffffu32 {
+            io_bitmap.trap_port(port as u16)?;
+        }
         vmcs.write_field(
             vmcs::VmcsField::IoBitmapA,
-            bitmap_a.start_address().as_u64(),
+            io_bitmap.bitmap_a().start_address().as_u64(),
         )?;
         vmcs.write_field(
             vmcs::VmcsField::IoBitmapB,
-            bitmap_b.start_address().as_u64(),
+            io_bitmap.bitmap_b().start_address().as_u64(),
         )?;
 
         let vapic_frame = alloc
@@ -305,32 +447,227 @@ impl VirtualMachine {
         )?;
         vmcs.write_field(vmcs::VmcsField::TprThreshold, 0)?;
 
-        Ok(())
+        Ok((msr_bitmap, io_bitmap))
     }
 
     pub fn launch(self, vmx: vmx::Vmx) -> Result<!> {
         // TODO: make this and store it in a per-cpu variable
-        // Ok(VirtualMachineRunning {
-        //     vmcs: self.vmcs.activate(vmx)?,
-        // })
-
-        self.vmcs.activate(vmx)?;
-
-        let rflags = unsafe {
-            let rflags: u64;
-            asm!("vmlaunch; pushfq; popq $0"
-                 : "=r"(rflags)
-                 :: "rflags"
-                 : "volatile");
-            rflags
-        };
-
-        error::check_vm_insruction(rflags, "Failed to launch vm".into())?;
-
-        unreachable!()
+        let vmcs = self.vmcs.activate(vmx)?;
+
+        VirtualMachineRunning {
+            vmcs,
+            gprs: vmx::GuestGprs::default(),
+            msr_bitmap: self.msr_bitmap,
+            io_bitmap: self.io_bitmap,
+            io_devices: self.io_devices,
+        }
+        .run()
     }
 }
 
+/// A port-IO-mapped device the IO-exit handler can dispatch `IN`/`OUT`
+/// to, registered against a specific port with
+/// `VirtualMachine::register_io_device`. `size` is the access width in
+/// bytes (1, 2, or 4).
+pub trait IoDevice {
+    fn read(&mut self, port: u16, size: u8) -> u32;
+    fn write(&mut self, port: u16, size: u8, value: u32);
+}
+
+/// A `VirtualMachine` that has been launched. Owns the
+/// activated VMCS and the guest's general-purpose registers -- VMX
+/// doesn't save RAX..R15 anywhere in the VMCS, so this is the only place
+/// they live between exits.
 pub struct VirtualMachineRunning {
     vmcs: vmcs::ActiveVmcs,
+    gprs: vmx::GuestGprs,
+    msr_bitmap: vmx::MsrBitmap,
+    io_bitmap: vmx::IoBitmap,
+    io_devices: Vec<(u16, alloc::boxed::Box<dyn IoDevice>)>,
+}
+
+/// The VCPU currently executing, if any. `vmx::vmexit_handler_wrapper`
+/// has no Rust frame to carry a reference through, so it reaches the
+/// running VM via this pointer instead. Revisit once this crate supports
+/// more than one logical processor.
+static mut RUNNING_VM: *mut VirtualMachineRunning = core::ptr::null_mut();
+
+impl VirtualMachineRunning {
+    /// Enter the guest and run it until it needs host intervention,
+    /// dispatch that exit, and repeat. Returns only if `vmlaunch` itself
+    /// fails, with the resulting error: once the guest has been entered,
+    /// every path back into Rust goes through
+    /// `vmx::vmexit_handler_wrapper` -> `Self::handle_exit` rather than
+    /// back to this call site.
+    fn run(mut self) -> Result<!> {
+        unsafe {
+            RUNNING_VM = &mut self as *mut _;
+            vmx::set_current_gprs(&mut self.gprs as *mut _);
+            vmx::set_exit_handler(Self::handle_exit);
+        }
+
+        let rflags = unsafe { vmx::vmlaunch() };
+
+        error::check_vm_insruction(rflags, "Failed to launch vm".into())?;
+        unreachable!()
+    }
+
+    /// The Rust half of `vmx::vmexit_handler_wrapper`, called once the
+    /// guest's GPRs are saved in `self.gprs`. Runs on the dedicated host
+    /// stack set up in `initialize_host_vmcs`, with nothing else on it:
+    /// if dispatch fails there is no Rust caller to unwind back into, so
+    /// this halts on error instead of returning. On success it does
+    /// return -- the asm in `vmexit_handler_wrapper` picks back up,
+    /// reloads `self.gprs` into the real registers, and issues
+    /// `vmresume`.
+    unsafe extern "C" fn handle_exit(gprs: *mut vmx::GuestGprs) {
+        let vm = &mut *RUNNING_VM;
+        let gprs = &mut *gprs;
+        debug_assert_eq!(gprs as *mut _, &mut vm.gprs as *mut _);
+
+        if let Err(e) = vm.dispatch_exit(gprs) {
+            error!("Failed to handle vm exit: {}", e);
+            loop {
+                asm!("cli; hlt" :::: "volatile");
+            }
+        }
+    }
+
+    fn dispatch_exit(&mut self, gprs: &mut vmx::GuestGprs) -> Result<()> {
+        let exit = vmx::VmExit::read(&self.vmcs)?;
+
+        match exit.reason {
+            // TODO: real CPUID/CR-access emulation, using `gprs` for the
+            // operands and results VMX doesn't put in the VMCS. For now
+            // just step over the trapping instruction.
+            vmx::ExitReason::Cpuid | vmx::ExitReason::CrAccess => self.advance_guest_rip(&exit),
+            vmx::ExitReason::IoInstruction => self.emulate_io(gprs, &exit),
+            vmx::ExitReason::RdMsr => self.emulate_rdmsr(gprs, &exit),
+            vmx::ExitReason::WrMsr => self.emulate_wrmsr(gprs, &exit),
+            // Nothing to do on the host side: the interrupt was already
+            // delivered by the time we see this exit.
+            vmx::ExitReason::ExternalInterrupt => Ok(()),
+            _ => self.unhandled_exit(&exit),
+        }
+    }
+
+    /// Emulate an `IN`/`OUT` trapped by the IO bitmap: decode the port,
+    /// direction, and access size out of `ExitQualification` (Intel SDM
+    /// Table 27-5) and dispatch to whatever `IoDevice` was registered for
+    /// that port, or log and ignore it if nothing claimed the port.
+    fn emulate_io(&mut self, gprs: &mut vmx::GuestGprs, exit: &vmx::VmExit) -> Result<()> {
+        let qualification = exit.qualification;
+        let size = match qualification & 0x7 {
+            0 => 1u8,
+            1 => 2u8,
+            3 => 4u8,
+            _ => return Err(Error::NotSupported("Unrecognized IO instruction size")),
+        };
+        let is_in = qualification & (1 << 3) != 0;
+        let is_string = qualification & (1 << 4) != 0;
+        let is_rep = qualification & (1 << 5) != 0;
+        let port = ((qualification >> 16) & 0xffff) as u16;
+
+        if is_string || is_rep {
+            return Err(Error::NotSupported(
+                "String/REP-prefixed IO instructions are not emulated",
+            ));
+        }
+
+        let mask = Self::io_mask(size);
+        let device = self
+            .io_devices
+            .iter_mut()
+            .find(|(p, _)| *p == port)
+            .map(|(_, device)| device);
+
+        match device {
+            Some(device) if is_in => {
+                let value = device.read(port, size) as u64;
+                gprs.rax = (gprs.rax & !mask) | (value & mask);
+            }
+            Some(device) => device.write(port, size, (gprs.rax & mask) as u32),
+            None => {
+                info!(
+                    "Ignoring {} on unregistered IO port 0x{:x}",
+                    if is_in { "IN" } else { "OUT" },
+                    port
+                );
+                if is_in {
+                    gprs.rax &= !mask;
+                }
+            }
+        }
+
+        self.advance_guest_rip(exit)
+    }
+
+    fn io_mask(size: u8) -> u64 {
+        match size {
+            1 => 0xff,
+            2 => 0xffff,
+            _ => 0xffff_ffff,
+        }
+    }
+
+    /// Open up `port` for direct guest access at runtime (e.g. once a
+    /// device decides it no longer needs to see a given access).
+    pub fn passthrough_port(&mut self, port: u16) -> Result<()> {
+        self.io_bitmap.passthrough_port(port)
+    }
+
+    /// Start trapping `port` again after `passthrough_port`.
+    pub fn trap_port(&mut self, port: u16) -> Result<()> {
+        self.io_bitmap.trap_port(port)
+    }
+
+    /// Emulate `RDMSR`: the guest selects an MSR in `ECX` and expects the
+    /// 64-bit result split across `EDX:EAX`. Only `IA32_EFER`, the one
+    /// MSR `initialize_ctrl_vmcs`'s `MsrBitmap` intercepts, is emulated;
+    /// any other MSR that traps here is logged and reads as 0.
+    fn emulate_rdmsr(&mut self, gprs: &mut vmx::GuestGprs, exit: &vmx::VmExit) -> Result<()> {
+        let value = match gprs.rcx as u32 {
+            registers::MSR_IA32_EFER => self.vmcs.read_field(vmcs::VmcsField::GuestIa32Efer)?,
+            msr => {
+                info!("Ignoring rdmsr of unvirtualized MSR 0x{:x}", msr);
+                0
+            }
+        };
+        gprs.rax = value & 0xffff_ffff;
+        gprs.rdx = value >> 32;
+        self.advance_guest_rip(exit)
+    }
+
+    /// Emulate `WRMSR`: the guest packs the value to write across
+    /// `EDX:EAX` and selects the MSR in `ECX`.
+    fn emulate_wrmsr(&mut self, gprs: &mut vmx::GuestGprs, exit: &vmx::VmExit) -> Result<()> {
+        let value = (gprs.rdx << 32) | (gprs.rax & 0xffff_ffff);
+        match gprs.rcx as u32 {
+            registers::MSR_IA32_EFER => {
+                self.vmcs.write_field(vmcs::VmcsField::GuestIa32Efer, value)?;
+            }
+            msr => info!("Ignoring wrmsr of unvirtualized MSR 0x{:x}", msr),
+        }
+        self.advance_guest_rip(exit)
+    }
+
+    fn advance_guest_rip(&mut self, exit: &vmx::VmExit) -> Result<()> {
+        self.vmcs
+            .write_field(vmcs::VmcsField::GuestRip, exit.guest_rip + exit.instr_len)
+    }
+
+    /// Fallback for any exit reason this crate doesn't emulate yet: log
+    /// it so it's debuggable, then halt rather than silently faulting or
+    /// resuming into a guest whose trap nobody serviced.
+    fn unhandled_exit(&mut self, exit: &vmx::VmExit) -> Result<()> {
+        error!(
+            "Unhandled vm exit: {:?} (qualification=0x{:x}, rip=0x{:x})",
+            exit.reason, exit.qualification, exit.guest_rip
+        );
+        unsafe {
+            loop {
+                asm!("cli; hlt" :::: "volatile");
+            }
+        }
+    }
 }
@@ -0,0 +1,327 @@
+//! The Virtual Machine Control Structure: field encodings, the VMREAD /
+//! VMWRITE wrappers, and the typestates that track whether a given VMCS
+//! is the one currently pointed to by `VMPTRLD`.
+
+use crate::error::{self, Error, Result};
+use crate::registers;
+use crate::vmx;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::frame::PhysFrame;
+use x86_64::structures::paging::page::Size4KiB;
+use x86_64::structures::paging::FrameAllocator;
+
+/// VMCS field encodings (Intel SDM Appendix B).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum VmcsField {
+    VirtualProcessorId = 0x0000,
+    GuestEsSelector = 0x0800,
+    GuestCsSelector = 0x0802,
+    GuestSsSelector = 0x0804,
+    GuestDsSelector = 0x0806,
+    GuestFsSelector = 0x0808,
+    GuestGsSelector = 0x080a,
+    GuestLdtrSelector = 0x080c,
+    GuestTrSelector = 0x080e,
+    HostEsSelector = 0x0c00,
+    HostCsSelector = 0x0c02,
+    HostSsSelector = 0x0c04,
+    HostDsSelector = 0x0c06,
+    HostFsSelector = 0x0c08,
+    HostGsSelector = 0x0c0a,
+    HostTrSelector = 0x0c0c,
+
+    IoBitmapA = 0x2000,
+    IoBitmapB = 0x2002,
+    MsrBitmap = 0x2004,
+    VmExitMsrStoreAddr = 0x2006,
+    VmExitMsrLoadAddr = 0x2008,
+    VmEntryMsrLoadAddr = 0x200a,
+    TscOffset = 0x2010,
+    VirtualApicPageAddr = 0x2012,
+    EptPointer = 0x201a,
+    VmcsLinkPointer = 0x2800,
+    VmcsLinkPointerHigh = 0x2801,
+    GuestIa32Efer = 0x2806,
+    HostIa32Efer = 0x2c02,
+
+    PinBasedVmExecControl = 0x4000,
+    CpuBasedVmExecControl = 0x4002,
+    ExceptionBitmap = 0x4004,
+    VmExitControls = 0x400c,
+    VmEntryControls = 0x4012,
+    TprThreshold = 0x401c,
+    SecondaryVmExecControl = 0x401e,
+    VmInstructionError = 0x4400,
+    VmExitReason = 0x4402,
+    VmExitInterruptionInfo = 0x4404,
+    VmExitInterruptionErrorCode = 0x4406,
+    VmExitInstructionLen = 0x440c,
+    GuestEsLimit = 0x4800,
+    GuestCsLimit = 0x4802,
+    GuestSsLimit = 0x4804,
+    GuestDsLimit = 0x4806,
+    GuestFsLimit = 0x4808,
+    GuestGsLimit = 0x480a,
+    GuestLdtrLimit = 0x480c,
+    GuestTrLimit = 0x480e,
+    GuestGdtrLimit = 0x4810,
+    GuestIdtrLimit = 0x4812,
+    GuestEsArBytes = 0x4814,
+    GuestCsArBytes = 0x4816,
+    GuestSsArBytes = 0x4818,
+    GuestDsArBytes = 0x481a,
+    GuestFsArBytes = 0x481c,
+    GuestGsArBytes = 0x481e,
+    GuestLdtrArBytes = 0x4820,
+    GuestTrArBytes = 0x4822,
+    GuestInterruptibilityInfo = 0x4824,
+    GuestActivityState = 0x4826,
+    HostIa32SysenterCs = 0x4c00,
+
+    ExitQualification = 0x6400,
+    GuestCr0 = 0x6800,
+    GuestCr3 = 0x6802,
+    GuestCr4 = 0x6804,
+    GuestEsBase = 0x6806,
+    GuestCsBase = 0x6808,
+    GuestSsBase = 0x680a,
+    GuestDsBase = 0x680c,
+    GuestFsBase = 0x680e,
+    GuestGsBase = 0x6810,
+    GuestLdtrBase = 0x6812,
+    GuestTrBase = 0x6814,
+    GuestGdtrBase = 0x6816,
+    GuestIdtrBase = 0x6818,
+    GuestDr7 = 0x681a,
+    GuestRsp = 0x681c,
+    GuestRip = 0x681e,
+    GuestRflags = 0x6820,
+    HostCr0 = 0x6c00,
+    HostCr3 = 0x6c02,
+    HostCr4 = 0x6c04,
+    HostFsBase = 0x6c06,
+    HostGsBase = 0x6c08,
+    HostTrBase = 0x6c0a,
+    HostGdtrBase = 0x6c0c,
+    HostIdtrBase = 0x6c0e,
+    HostIa32SysenterEsp = 0x6c10,
+    HostIa32SysenterEip = 0x6c12,
+    HostRsp = 0x6c14,
+    HostRip = 0x6c16,
+}
+
+bitflags! {
+    pub struct CpuBasedCtrlFlags: u64 {
+        const INTERRUPT_WINDOW_EXITING = 1 << 2;
+        const USE_TSC_OFFSETTING = 1 << 3;
+        const HLT_EXITING = 1 << 7;
+        const INVLPG_EXITING = 1 << 9;
+        const MWAIT_EXITING = 1 << 10;
+        const RDPMC_EXITING = 1 << 11;
+        const RDTSC_EXITING = 1 << 12;
+        const CR3_LOAD_EXITING = 1 << 15;
+        const CR3_STORE_EXITING = 1 << 16;
+        const CR8_LOAD_EXITING = 1 << 19;
+        const CR8_STORE_EXITING = 1 << 20;
+        const USE_TPR_SHADOW = 1 << 21;
+        const NMI_WINDOW_EXITING = 1 << 22;
+        const MOV_DR_EXITING = 1 << 23;
+        const UNCONDITIONAL_IO_EXITING = 1 << 24;
+        const USE_IO_BITMAPS = 1 << 25;
+        const MONITOR_TRAP_FLAG = 1 << 27;
+        const USE_MSR_BITMAPS = 1 << 28;
+        const MONITOR_EXITING = 1 << 29;
+        const PAUSE_EXITING = 1 << 30;
+        const ACTIVATE_SECONDARY_CONTROLS = 1 << 31;
+    }
+}
+
+bitflags! {
+    pub struct SecondaryExecFlags: u64 {
+        const VIRTUALIZE_APIC_ACCESSES = 1 << 0;
+        const ENABLE_EPT = 1 << 1;
+        const DESCRIPTOR_TABLE_EXITING = 1 << 2;
+        const ENABLE_RDTSCP = 1 << 3;
+        const VIRTUALIZE_X2APIC_MODE = 1 << 4;
+        const ENABLE_VPID = 1 << 5;
+        const WBINVD_EXITING = 1 << 6;
+        const UNRESTRICTED_GUEST = 1 << 7;
+        const APIC_REGISTER_VIRTUALIZATION = 1 << 8;
+        const VIRTUAL_INTERRUPT_DELIVERY = 1 << 9;
+        const PAUSE_LOOP_EXITING = 1 << 10;
+        const RDRAND_EXITING = 1 << 11;
+        const ENABLE_INVPCID = 1 << 12;
+        const ENABLE_VM_FUNCTIONS = 1 << 13;
+        const VMCS_SHADOWING = 1 << 14;
+        const RDSEED_EXITING = 1 << 16;
+        const ENABLE_PML = 1 << 17;
+        const EPT_VIOLATION_VE = 1 << 18;
+    }
+}
+
+bitflags! {
+    pub struct VmExitCtrlFlags: u64 {
+        const SAVE_DEBUG_CONTROLS = 1 << 2;
+        const IA32E_MODE = 1 << 9;
+        const LOAD_IA32_PERF_GLOBAL_CTRL = 1 << 12;
+        const ACK_INTERRUPT_ON_EXIT = 1 << 15;
+        const SAVE_IA32_PAT = 1 << 18;
+        const LOAD_IA32_PAT = 1 << 19;
+        const SAVE_IA32_EFER = 1 << 20;
+        const LOAD_IA32_EFER = 1 << 21;
+    }
+}
+
+bitflags! {
+    pub struct VmEntryCtrlFlags: u64 {
+        const LOAD_DEBUG_CONTROLS = 1 << 2;
+        const IA32E_MODE_GUEST = 1 << 9;
+        const ENTRY_TO_SMM = 1 << 10;
+        const LOAD_IA32_PERF_GLOBAL_CTRL = 1 << 13;
+        const LOAD_IA32_PAT = 1 << 14;
+        const LOAD_IA32_EFER = 1 << 15;
+    }
+}
+
+fn vmwrite(field: VmcsField, value: u64) -> Result<()> {
+    let rflags: u64;
+    unsafe {
+        asm!("vmwrite $1, $2; pushfq; popq $0"
+             : "=r"(rflags)
+             : "r"(value), "r"(field as u64)
+             : "rflags"
+             : "volatile");
+    }
+    error::check_vm_insruction(rflags, format!("Failed to write field {:?}", field))
+}
+
+fn vmread(field: VmcsField) -> Result<u64> {
+    let value: u64;
+    let rflags: u64;
+    unsafe {
+        asm!("vmread $2, $1; pushfq; popq $0"
+             : "=r"(rflags), "=r"(value)
+             : "r"(field as u64)
+             : "rflags"
+             : "volatile");
+    }
+    error::check_vm_insruction(rflags, format!("Failed to read field {:?}", field))?;
+    Ok(value)
+}
+
+/// A VMCS that is not currently loaded by `VMPTRLD` on any logical
+/// processor. Most interaction happens through `with_active_vmcs`, which
+/// temporarily loads it so fields can be written during setup.
+pub struct Vmcs {
+    region: PhysFrame<Size4KiB>,
+}
+
+impl Vmcs {
+    pub fn new(alloc: &mut impl FrameAllocator<Size4KiB>) -> Result<Self> {
+        let region = alloc
+            .allocate_frame()
+            .ok_or(Error::AllocError("Failed to allocate VMCS region"))?;
+
+        let revision_id = unsafe { Msr::new(registers::MSR_IA32_VMX_BASIC).read() as u32 };
+        let ptr = region.start_address().as_u64() as *mut u32;
+        unsafe { *ptr = revision_id };
+
+        Ok(Vmcs { region })
+    }
+
+    fn vmclear(&self) -> Result<()> {
+        let addr = self.region.start_address().as_u64();
+        let rflags: u64;
+        unsafe {
+            asm!("vmclear $1; pushfq; popq $0"
+                 : "=r"(rflags)
+                 : "*m"(&addr)
+                 : "rflags"
+                 : "volatile");
+        }
+        error::check_vm_insruction(rflags, "Failed to clear VMCS".into())
+    }
+
+    fn vmptrld(&self) -> Result<()> {
+        let addr = self.region.start_address().as_u64();
+        let rflags: u64;
+        unsafe {
+            asm!("vmptrld $1; pushfq; popq $0"
+                 : "=r"(rflags)
+                 : "*m"(&addr)
+                 : "rflags"
+                 : "volatile");
+        }
+        error::check_vm_insruction(rflags, "Failed to load VMCS pointer".into())
+    }
+
+    /// Load this VMCS as the current one via `VMPTRLD`, run `f` against
+    /// it, then `VMCLEAR` it again so it's safe to hand to another
+    /// logical processor or to activate for real later on. `f` may hand
+    /// back anything it built along the way (e.g. an `IoBitmap` whose
+    /// frames need to outlive the closure) as `T`.
+    pub fn with_active_vmcs<F, T>(&mut self, vmx: &mut vmx::Vmx, f: F) -> Result<T>
+    where
+        F: FnOnce(TemporaryActiveVmcs) -> Result<T>,
+    {
+        let _ = vmx;
+        self.vmclear()?;
+        self.vmptrld()?;
+        let result = f(TemporaryActiveVmcs { vmcs: self });
+        self.vmclear()?;
+        result
+    }
+
+    /// Consume this VMCS and make it the one the current logical
+    /// processor will enter (`VMLAUNCH`/`VMRESUME` will target it).
+    pub fn activate(self, vmx: vmx::Vmx) -> Result<ActiveVmcs> {
+        self.vmptrld()?;
+        Ok(ActiveVmcs { vmcs: self, vmx })
+    }
+}
+
+/// A VMCS loaded via `VMPTRLD` for the duration of a setup closure.
+pub struct TemporaryActiveVmcs<'a> {
+    vmcs: &'a mut Vmcs,
+}
+
+impl<'a> TemporaryActiveVmcs<'a> {
+    pub fn write_field(&mut self, field: VmcsField, value: u64) -> Result<()> {
+        vmwrite(field, value)
+    }
+
+    pub fn read_field(&self, field: VmcsField) -> Result<u64> {
+        vmread(field)
+    }
+
+    /// Write `desired` into `field`, first clamping it against the
+    /// allowed-0/allowed-1 settings reported by `msr` (the "true" or
+    /// plain capability MSR for that control field, per SDM A.3.1):
+    /// bits set in the MSR's low dword (allowed-0) are forced on, bits
+    /// clear in its high dword (allowed-1) are forced off.
+    pub fn write_with_fixed(&mut self, field: VmcsField, desired: u64, msr: u32) -> Result<()> {
+        let caps = unsafe { Msr::new(msr).read() };
+        let allowed0 = caps & 0xffff_ffff;
+        let allowed1 = caps >> 32;
+        let value = (desired | allowed0) & allowed1;
+        self.write_field(field, value)
+    }
+}
+
+/// A VMCS that has been activated for actual guest execution (as opposed
+/// to one loaded transiently just to configure it).
+pub struct ActiveVmcs {
+    vmcs: Vmcs,
+    vmx: vmx::Vmx,
+}
+
+impl ActiveVmcs {
+    pub fn write_field(&mut self, field: VmcsField, value: u64) -> Result<()> {
+        vmwrite(field, value)
+    }
+
+    pub fn read_field(&self, field: VmcsField) -> Result<u64> {
+        vmread(field)
+    }
+}
@@ -0,0 +1,436 @@
+//! Low-level VMX mechanics: entering/exiting the guest and decoding why a
+//! VM exit happened. The policy of what to *do* about a given exit lives
+//! in `vm::VirtualMachineRunning`; this module only knows how to get in
+//! and out of non-root operation.
+
+use crate::error::{Error, Result};
+use crate::vmcs::{self, VmcsField};
+use x86_64::structures::paging::frame::PhysFrame;
+use x86_64::structures::paging::page::Size4KiB;
+use x86_64::structures::paging::FrameAllocator;
+
+/// A handle to VMX operation on the current logical processor (i.e.
+/// `VMXON` has already been executed). Enabling/disabling VMX operation
+/// itself is out of scope here; this is just something to thread through
+/// `Vmcs::activate` so a `VirtualMachine` can prove it holds the right to
+/// enter non-root operation.
+pub struct Vmx {
+    vmxon_region: PhysFrame<Size4KiB>,
+}
+
+impl Vmx {
+    pub fn new(vmxon_region: PhysFrame<Size4KiB>) -> Self {
+        Vmx { vmxon_region }
+    }
+
+    pub fn vmxon_region(&self) -> PhysFrame<Size4KiB> {
+        self.vmxon_region
+    }
+}
+
+/// A 4 KiB page of read/write sub-bitmaps controlling which `RDMSR`/
+/// `WRMSR` accesses trap into the host instead of executing directly
+/// (Intel SDM 24.6.9). The page is laid out as four 1024-byte regions:
+/// read bitmap for MSRs 0x0000_0000-0x0000_1fff, read bitmap for
+/// 0xc000_0000-0xc000_1fff, then the same two ranges again for writes.
+pub struct MsrBitmap {
+    frame: PhysFrame<Size4KiB>,
+}
+
+const MSR_BITMAP_READ_LOW: usize = 0x000;
+const MSR_BITMAP_READ_HIGH: usize = 0x400;
+const MSR_BITMAP_WRITE_LOW: usize = 0x800;
+const MSR_BITMAP_WRITE_HIGH: usize = 0xc00;
+const MSR_BITMAP_HIGH_BASE: u32 = 0xc000_0000;
+
+impl MsrBitmap {
+    pub fn new(alloc: &mut impl FrameAllocator<Size4KiB>) -> Result<Self> {
+        let frame = alloc
+            .allocate_frame()
+            .ok_or(Error::AllocError("Failed to allocate MSR bitmap"))?;
+        let bytes = unsafe { &mut *(frame.start_address().as_u64() as *mut [u8; 4096]) };
+        for byte in bytes.iter_mut() {
+            *byte = 0;
+        }
+        Ok(MsrBitmap { frame })
+    }
+
+    pub fn frame(&self) -> PhysFrame<Size4KiB> {
+        self.frame
+    }
+
+    /// Trap `RDMSR` for `msr` into the host.
+    pub fn intercept_read(&mut self, msr: u32) -> Result<()> {
+        let region = Self::region(msr, MSR_BITMAP_READ_LOW, MSR_BITMAP_READ_HIGH)?;
+        self.set_bit(region, msr, true)
+    }
+
+    /// Trap `WRMSR` for `msr` into the host.
+    pub fn intercept_write(&mut self, msr: u32) -> Result<()> {
+        let region = Self::region(msr, MSR_BITMAP_WRITE_LOW, MSR_BITMAP_WRITE_HIGH)?;
+        self.set_bit(region, msr, true)
+    }
+
+    /// Let the guest execute `RDMSR`/`WRMSR` for `msr` directly, without
+    /// trapping into the host.
+    pub fn pass_through(&mut self, msr: u32) -> Result<()> {
+        let read_region = Self::region(msr, MSR_BITMAP_READ_LOW, MSR_BITMAP_READ_HIGH)?;
+        self.set_bit(read_region, msr, false)?;
+        let write_region = Self::region(msr, MSR_BITMAP_WRITE_LOW, MSR_BITMAP_WRITE_HIGH)?;
+        self.set_bit(write_region, msr, false)
+    }
+
+    fn region(msr: u32, low: usize, high: usize) -> Result<usize> {
+        if msr <= 0x1fff {
+            Ok(low)
+        } else if (MSR_BITMAP_HIGH_BASE..=MSR_BITMAP_HIGH_BASE + 0x1fff).contains(&msr) {
+            Ok(high)
+        } else {
+            Err(Error::NotSupported("MSR is outside the bitmap's ranges"))
+        }
+    }
+
+    fn set_bit(&mut self, region: usize, msr: u32, trap: bool) -> Result<()> {
+        let index = (msr & 0x1fff) as usize;
+        let byte_offset = region + index / 8;
+        let bit = index % 8;
+
+        let ptr = (self.frame.start_address().as_u64() as usize + byte_offset) as *mut u8;
+        unsafe {
+            if trap {
+                *ptr |= 1 << bit;
+            } else {
+                *ptr &= !(1 << bit);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A pair of 4 KiB bitmaps controlling which `IN`/`OUT` accesses trap
+/// into the host instead of executing directly (Intel SDM 24.6.4):
+/// `bitmap_a` covers ports 0x0000-0x7fff and `bitmap_b` covers
+/// 0x8000-0xffff, one bit per port. This crate has no frame
+/// deallocator, so the underlying frames live for the rest of the
+/// program regardless; what matters is that `VirtualMachine` keeps the
+/// handle, rather than dropping it once the VMCS is programmed, so
+/// `passthrough_port`/`trap_port` can still mutate the bitmap later.
+pub struct IoBitmap {
+    bitmap_a: PhysFrame<Size4KiB>,
+    bitmap_b: PhysFrame<Size4KiB>,
+}
+
+impl IoBitmap {
+    pub fn new(alloc: &mut impl FrameAllocator<Size4KiB>) -> Result<Self> {
+        let bitmap_a = alloc
+            .allocate_frame()
+            .ok_or(Error::AllocError("Failed to allocate IO bitmap"))?;
+        let bitmap_b = alloc
+            .allocate_frame()
+            .ok_or(Error::AllocError("Failed to allocate IO bitmap"))?;
+        for frame in [bitmap_a, bitmap_b].iter() {
+            let bytes = unsafe { &mut *(frame.start_address().as_u64() as *mut [u8; 4096]) };
+            for byte in bytes.iter_mut() {
+                *byte = 0;
+            }
+        }
+        Ok(IoBitmap { bitmap_a, bitmap_b })
+    }
+
+    pub fn bitmap_a(&self) -> PhysFrame<Size4KiB> {
+        self.bitmap_a
+    }
+
+    pub fn bitmap_b(&self) -> PhysFrame<Size4KiB> {
+        self.bitmap_b
+    }
+
+    /// Trap `IN`/`OUT` on `port` into the host.
+    pub fn trap_port(&mut self, port: u16) -> Result<()> {
+        self.set_bit(port, true)
+    }
+
+    /// Let the guest execute `IN`/`OUT` on `port` directly, without
+    /// trapping into the host.
+    pub fn passthrough_port(&mut self, port: u16) -> Result<()> {
+        self.set_bit(port, false)
+    }
+
+    fn set_bit(&mut self, port: u16, trap: bool) -> Result<()> {
+        let (frame, index) = if port < 0x8000 {
+            (self.bitmap_a, port)
+        } else {
+            (self.bitmap_b, port - 0x8000)
+        };
+        let byte_offset = (index / 8) as usize;
+        let bit = index % 8;
+
+        let ptr = (frame.start_address().as_u64() as usize + byte_offset) as *mut u8;
+        unsafe {
+            if trap {
+                *ptr |= 1 << bit;
+            } else {
+                *ptr &= !(1 << bit);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The basic VM-exit reason (VM-exit reason field, bits 0-15 -- see
+/// Intel SDM Appendix C). Only the reasons this crate currently acts on
+/// (or deliberately wants to name for debugging) get a variant; anything
+/// else falls through to `Unknown` so new exit reasons don't need a
+/// matching enum update before they can at least be logged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    ExceptionOrNmi,
+    ExternalInterrupt,
+    TripleFault,
+    Cpuid,
+    Hlt,
+    VmCall,
+    CrAccess,
+    MovDr,
+    IoInstruction,
+    RdMsr,
+    WrMsr,
+    EptViolation,
+    EptMisconfiguration,
+    Unknown(u16),
+}
+
+impl From<u16> for ExitReason {
+    fn from(reason: u16) -> Self {
+        match reason {
+            0 => ExitReason::ExceptionOrNmi,
+            1 => ExitReason::ExternalInterrupt,
+            2 => ExitReason::TripleFault,
+            10 => ExitReason::Cpuid,
+            12 => ExitReason::Hlt,
+            18 => ExitReason::VmCall,
+            28 => ExitReason::CrAccess,
+            29 => ExitReason::MovDr,
+            30 => ExitReason::IoInstruction,
+            31 => ExitReason::RdMsr,
+            32 => ExitReason::WrMsr,
+            48 => ExitReason::EptViolation,
+            49 => ExitReason::EptMisconfiguration,
+            reason => ExitReason::Unknown(reason),
+        }
+    }
+}
+
+/// Everything the dispatch loop needs to know about why the guest
+/// exited, decoded from the active VMCS right after the exit.
+#[derive(Debug, Clone, Copy)]
+pub struct VmExit {
+    pub reason: ExitReason,
+    pub qualification: u64,
+    pub guest_rip: u64,
+    pub instr_len: u64,
+}
+
+impl VmExit {
+    /// Read the VM-exit information currently latched in the active VMCS.
+    pub fn read(vmcs: &vmcs::ActiveVmcs) -> Result<Self> {
+        let raw_reason = vmcs.read_field(VmcsField::VmExitReason)?;
+        Ok(VmExit {
+            reason: ExitReason::from((raw_reason & 0xffff) as u16),
+            qualification: vmcs.read_field(VmcsField::ExitQualification)?,
+            guest_rip: vmcs.read_field(VmcsField::GuestRip)?,
+            instr_len: vmcs.read_field(VmcsField::VmExitInstructionLen)?,
+        })
+    }
+}
+
+/// The guest's general-purpose registers. VMX only saves/restores
+/// `RIP`/`RSP`/`RFLAGS` (plus the handful of other VMCS-resident state)
+/// across VM entry/exit -- RAX..R15 are left exactly as the guest set
+/// them, so the host has to stash them away before they get clobbered by
+/// any exit-handling code and reload them immediately before the next
+/// `vmlaunch`/`vmresume`. `repr(C)` and a fixed field order so
+/// `vmexit_handler_wrapper` can address each register by a constant
+/// offset; keep the two in sync.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GuestGprs {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// The `GuestGprs` block `vmexit_handler_wrapper` saves into and reloads
+/// from. Set by `VirtualMachineRunning::run` before the first
+/// `vmlaunch`; read back via rip-relative addressing by the trampoline,
+/// which has no other way to find it (no Rust frame, and using a
+/// register to hold the pointer would mean clobbering a guest register
+/// before it's saved).
+#[no_mangle]
+static mut CURRENT_GPRS: *mut GuestGprs = core::ptr::null_mut();
+
+/// Point `vmexit_handler_wrapper` at the `GuestGprs` block to save into
+/// and restore from.
+///
+/// # Safety
+/// Must be called before the first `vmlaunch`, and `gprs` must stay
+/// valid for as long as the guest keeps running (i.e. for the lifetime
+/// of the owning `VirtualMachineRunning`).
+pub unsafe fn set_current_gprs(gprs: *mut GuestGprs) {
+    CURRENT_GPRS = gprs;
+}
+
+/// Registered by `VirtualMachineRunning::run` before the initial
+/// `vmlaunch`; invoked by `vmexit_rust_handler` on every VM exit with a
+/// pointer to the now up-to-date `GuestGprs` block. A raw function
+/// pointer (rather than a closure/trait object) because it is called
+/// from a context with no Rust frame to capture one in.
+type ExitHandler = unsafe extern "C" fn(*mut GuestGprs);
+
+static mut EXIT_HANDLER: Option<ExitHandler> = None;
+
+/// Install the handler `vmexit_rust_handler` calls on VM exit.
+///
+/// # Safety
+/// Must be called before the first `vmlaunch`, and only from the logical
+/// processor that will run the guest (this is genuinely global, not
+/// per-vcpu, until this crate supports more than one VCPU).
+pub unsafe fn set_exit_handler(handler: ExitHandler) {
+    EXIT_HANDLER = Some(handler);
+}
+
+/// The Rust-side landing pad `vmexit_handler_wrapper` calls once the
+/// guest's GPRs are safely stashed in `CURRENT_GPRS` and it's safe to use
+/// the stack/registers again. Named and `#[no_mangle]` so the naked `call`
+/// below can reach it by symbol.
+#[no_mangle]
+unsafe extern "C" fn vmexit_rust_handler(gprs: *mut GuestGprs) {
+    if let Some(handler) = EXIT_HANDLER {
+        handler(gprs);
+    }
+}
+
+/// The VMX host-entry point: the address written to `HostRip` so the
+/// processor lands here on every VM exit (`HostRsp` is the dedicated
+/// stack allocated alongside the VMCS in `VirtualMachine::new`). `#[naked]`
+/// because the very first instructions here have to run before the
+/// guest's RAX..R15 (still sitting in the physical registers at this
+/// point) are touched by any ordinary function prologue.
+///
+/// Saves the guest GPRs into `CURRENT_GPRS`, calls `vmexit_rust_handler`
+/// to decode and dispatch the exit, reloads GPRs from `CURRENT_GPRS`
+/// (picking up anything the handler mutated, e.g. a `CPUID` result), and
+/// resumes the guest. Never returns: if `vmresume` itself fails, there is
+/// no caller on this stack to unwind back into, so halt instead.
+#[naked]
+pub unsafe extern "C" fn vmexit_handler_wrapper() -> ! {
+    asm!("
+        push rax
+        mov rax, [rip + CURRENT_GPRS]
+        mov [rax + 8], rbx
+        mov [rax + 16], rcx
+        mov [rax + 24], rdx
+        mov [rax + 32], rsi
+        mov [rax + 40], rdi
+        mov [rax + 48], rbp
+        mov [rax + 56], r8
+        mov [rax + 64], r9
+        mov [rax + 72], r10
+        mov [rax + 80], r11
+        mov [rax + 88], r12
+        mov [rax + 96], r13
+        mov [rax + 104], r14
+        mov [rax + 112], r15
+        pop rcx
+        mov [rax + 0], rcx
+
+        mov rdi, rax
+        call vmexit_rust_handler
+
+        mov rax, [rip + CURRENT_GPRS]
+        mov rbx, [rax + 8]
+        mov rcx, [rax + 16]
+        mov rdx, [rax + 24]
+        mov rsi, [rax + 32]
+        mov rdi, [rax + 40]
+        mov rbp, [rax + 48]
+        mov r8,  [rax + 56]
+        mov r9,  [rax + 64]
+        mov r10, [rax + 72]
+        mov r11, [rax + 80]
+        mov r12, [rax + 88]
+        mov r13, [rax + 96]
+        mov r14, [rax + 104]
+        mov r15, [rax + 112]
+        mov rax, [rax + 0]
+
+        vmresume
+        cli
+        hlt
+    "
+    :::: "intel", "volatile");
+    unreachable!()
+}
+
+/// Load the guest's GPRs from `CURRENT_GPRS` into the physical registers
+/// and execute `vmlaunch`, then return the resulting `RFLAGS` so the
+/// caller can turn CF/ZF into a proper `Error` via
+/// `error::check_vm_insruction`. Unlike `vmresume`, which is only ever
+/// reached through `vmexit_handler_wrapper` and so always runs right
+/// after that trampoline reloaded RAX..R15 from `CURRENT_GPRS`, this is
+/// called from ordinary Rust, so it has to do that priming itself --
+/// otherwise the guest's first entry would run with whatever Rust's own
+/// codegen left in those registers instead of the configured
+/// `GuestGprs` state.
+pub unsafe fn vmlaunch() -> u64 {
+    let rflags: u64;
+    asm!("
+        movq CURRENT_GPRS(%rip), %rax
+        movq 8(%rax), %rbx
+        movq 16(%rax), %rcx
+        movq 24(%rax), %rdx
+        movq 32(%rax), %rsi
+        movq 40(%rax), %rdi
+        movq 48(%rax), %rbp
+        movq 56(%rax), %r8
+        movq 64(%rax), %r9
+        movq 72(%rax), %r10
+        movq 80(%rax), %r11
+        movq 88(%rax), %r12
+        movq 96(%rax), %r13
+        movq 104(%rax), %r14
+        movq 112(%rax), %r15
+        movq (%rax), %rax
+
+        vmlaunch
+        pushfq
+        popq $0
+        "
+         : "=r"(rflags)
+         :: "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp",
+            "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15", "rflags"
+         : "volatile");
+    rflags
+}
+
+/// Execute `vmresume` and return the resulting `RFLAGS` (see `vmlaunch`).
+pub unsafe fn vmresume() -> u64 {
+    let rflags: u64;
+    asm!("vmresume; pushfq; popq $0"
+         : "=r"(rflags)
+         :: "rflags"
+         : "volatile");
+    rflags
+}